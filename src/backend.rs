@@ -0,0 +1,224 @@
+//! Pluggable storage backends for the WAL's raw byte region.
+//!
+//! `StufferShack` only ever needs a growable region of bytes it can cast
+//! typed values in and out of via [`UncheckedCast`]/[`UncheckedCastMut`].
+//! [`StoreBackend`] abstracts over how that region is actually backed, so
+//! the record/index logic doesn't have to talk to the OS mmap API directly
+//! and can run equally well over a file, anonymous memory, or a plain
+//! heap buffer.
+
+use std::{
+    fs,
+    io,
+    ops::{Deref, DerefMut},
+};
+
+use memmap::{MmapMut, MmapOptions};
+
+/// A growable region of bytes that typed values can be read from and
+/// written to directly, with no (de)serialization step.
+pub(crate) trait StoreBackend: Deref<Target = [u8]> + DerefMut<Target = [u8]> {
+    /// Current size of the backing region, in bytes.
+    fn len(&self) -> usize;
+
+    /// Grows the region to at least `new_len` bytes, preserving existing
+    /// contents.
+    ///
+    /// Implementations may need to remap or reallocate to do this, which
+    /// invalidates any borrows previously handed out via `at`/`at_mut` --
+    /// callers must only hold onto offsets, never references, across a
+    /// `grow`.
+    fn grow(&mut self, new_len: usize) -> io::Result<()>;
+
+    /// Raw pointer to the start of the backing region, usable to write
+    /// through even while only holding `&self` (e.g. a shared
+    /// `RwLockReadGuard`) -- see `crate::write_record`'s safety section for
+    /// why that's necessary and sound.
+    ///
+    /// Implementations must cache this directly from the `&mut` access they
+    /// already have at construction and after every `grow`, and hand out
+    /// that cached copy here -- never re-derive it by reborrowing `&self` as
+    /// `&[u8]` and casting. The latter would tag the memory read-only under
+    /// Stacked/Tree Borrows, making any later write through it undefined
+    /// behaviour even though the underlying memory is perfectly writable.
+    fn write_ptr(&self) -> *mut u8;
+}
+
+/// A backend mapped from a file on disk.
+#[derive(Debug)]
+pub(crate) struct FileBackend {
+    file: fs::File,
+    map: MmapMut,
+    /// Cached copy of `map`'s own pointer; see [`StoreBackend::write_ptr`].
+    ptr: *mut u8,
+}
+
+// SAFETY: `ptr` is just a cached copy of `map`'s own pointer (refreshed in
+// `open`/`grow`, whenever `map` changes), so it carries exactly the same
+// thread-safety as `map: MmapMut` itself; it doesn't introduce any aliasing
+// that `map` didn't already represent, it just lets `write_ptr` hand it out
+// from `&self`.
+unsafe impl Send for FileBackend {}
+unsafe impl Sync for FileBackend {}
+
+impl FileBackend {
+    /// Opens `file` as a memory-mapped backend, growing it to `len` bytes
+    /// first if it is smaller.
+    pub(crate) fn open(file: fs::File, len: usize) -> io::Result<Self> {
+        let current_len = file.metadata()?.len();
+        if current_len < len as u64 {
+            file.set_len(len as u64)?;
+        }
+        let mut map = unsafe { MmapOptions::new().len(len).map_mut(&file)? };
+        let ptr = map.as_mut_ptr();
+        Ok(FileBackend { file, map, ptr })
+    }
+}
+
+impl Deref for FileBackend {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl DerefMut for FileBackend {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.map
+    }
+}
+
+impl StoreBackend for FileBackend {
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn grow(&mut self, new_len: usize) -> io::Result<()> {
+        if new_len <= self.map.len() {
+            return Ok(());
+        }
+
+        self.file.set_len(new_len as u64)?;
+        self.map = unsafe { MmapOptions::new().len(new_len).map_mut(&self.file)? };
+        self.ptr = self.map.as_mut_ptr();
+        Ok(())
+    }
+
+    fn write_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+}
+
+/// A backend mapped anonymously (no backing file), used for ephemeral
+/// databases.
+#[derive(Debug)]
+pub(crate) struct AnonBackend {
+    map: MmapMut,
+    /// Cached copy of `map`'s own pointer; see [`StoreBackend::write_ptr`].
+    ptr: *mut u8,
+}
+
+// SAFETY: see the identical justification on `FileBackend`'s impls.
+unsafe impl Send for AnonBackend {}
+unsafe impl Sync for AnonBackend {}
+
+impl AnonBackend {
+    pub(crate) fn new(len: usize) -> io::Result<Self> {
+        let mut map = unsafe { MmapOptions::new().len(len).map_anon()? };
+        let ptr = map.as_mut_ptr();
+        Ok(AnonBackend { map, ptr })
+    }
+}
+
+impl Deref for AnonBackend {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl DerefMut for AnonBackend {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.map
+    }
+}
+
+impl StoreBackend for AnonBackend {
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn grow(&mut self, new_len: usize) -> io::Result<()> {
+        if new_len <= self.map.len() {
+            return Ok(());
+        }
+
+        let mut new_map = unsafe { MmapOptions::new().len(new_len).map_anon()? };
+        new_map[..self.map.len()].copy_from_slice(&self.map);
+        self.map = new_map;
+        self.ptr = self.map.as_mut_ptr();
+        Ok(())
+    }
+
+    fn write_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+}
+
+/// A plain in-memory backend with no mmap involved at all, for tests and
+/// for environments where mmap is unavailable. Backed by a `Vec<u8>` (not
+/// a `Box<[u8]>`) specifically so it can grow via `Vec::resize` rather than
+/// a full reallocation-and-copy on every insert.
+#[derive(Debug, Default)]
+pub(crate) struct MemBackend {
+    buf: Vec<u8>,
+    /// Cached copy of `buf`'s own pointer; see [`StoreBackend::write_ptr`].
+    ptr: *mut u8,
+}
+
+// SAFETY: see the identical justification on `FileBackend`'s impls --
+// `Vec<u8>` is already `Send + Sync`, `ptr` just caches its pointer.
+unsafe impl Send for MemBackend {}
+unsafe impl Sync for MemBackend {}
+
+impl MemBackend {
+    pub(crate) fn new(len: usize) -> Self {
+        let mut buf = vec![0; len];
+        let ptr = buf.as_mut_ptr();
+        MemBackend { buf, ptr }
+    }
+}
+
+impl Deref for MemBackend {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for MemBackend {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl StoreBackend for MemBackend {
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn grow(&mut self, new_len: usize) -> io::Result<()> {
+        if new_len > self.buf.len() {
+            self.buf.resize(new_len, 0);
+            self.ptr = self.buf.as_mut_ptr();
+        }
+        Ok(())
+    }
+
+    fn write_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+}
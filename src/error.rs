@@ -13,10 +13,18 @@ pub enum StufferShackError {
     /// Error initialising the database.
     #[error("database invalid")]
     DatabaseInit(#[source] InvalidDatabaseError),
+    /// Error encountered while reading or writing a record, as opposed to
+    /// while opening/initialising the database itself.
+    #[error("invalid record access")]
+    InvalidAccess(#[source] InvalidDatabaseError),
+    /// Error growing the backend while making room for a new record, e.g. a
+    /// failed `set_len`/remap. See [`crate::StufferShack::ensure_capacity`].
+    #[error("could not grow database backend")]
+    BackendGrow(#[source] io::Error),
 }
 
 /// A database (header) validation error.
-#[derive(Copy, Clone, Debug, Error)]
+#[derive(Debug, Error)]
 pub enum InvalidDatabaseError {
     /// First bytes were not equal to the magic file header.
     #[error("invalid magic at start of file")]
@@ -41,4 +49,80 @@ pub enum InvalidDatabaseError {
     /// The key length given at compile time is too large to fit a `u16`.
     #[error("key length overflow")]
     KeyLengthOverflow,
+    /// A record's header (or its declared value) ran past the end of the
+    /// valid region, most likely because a crash cut off the last write.
+    #[error("truncated record at offset {offset}")]
+    TruncatedRecord {
+        /// Byte offset of the truncated record's header.
+        offset: u64,
+    },
+    /// A record's declared size overflowed while being validated, which
+    /// cannot happen from a partial tail write and indicates the log is
+    /// corrupt rather than merely interrupted.
+    #[error("record size overflow at offset {offset} (size {size})")]
+    RecordSizeOverflow {
+        /// Byte offset of the record whose size overflowed.
+        offset: u64,
+        /// The size that overflowed.
+        size: u64,
+    },
+    /// A checked offset computation (see [`crate::offset::SafeOffset`])
+    /// overflowed `u64`, or a valid `u64` offset didn't fit in `usize` on
+    /// this platform. Neither can happen against a database this crate
+    /// wrote itself, so it indicates a corrupt file.
+    #[error("offset overflow computing {base} (operand {operand})")]
+    OffsetOverflow {
+        /// The offset the failed computation started from.
+        base: u64,
+        /// The value being added to or multiplied with `base`.
+        operand: u64,
+    },
+    /// `DatabaseHeader::compression` held a tag this version of the crate
+    /// doesn't know how to decode.
+    #[error("unknown compression type {compression}")]
+    UnknownCompressionType {
+        /// The unrecognised tag.
+        compression: u8,
+    },
+    /// A record's `flags` byte held a tag this version of the crate doesn't
+    /// know how to decode.
+    #[error("unknown record flags {flags}")]
+    UnknownRecordFlags {
+        /// The unrecognised tag.
+        flags: u8,
+    },
+    /// LZ4 decompression of a record's value failed, e.g. because the
+    /// compressed bytes or the declared uncompressed length are corrupt.
+    #[error("decompression failed for record at offset {offset}")]
+    DecompressionFailed {
+        /// Byte offset of the record whose value failed to decompress.
+        offset: u64,
+    },
+    /// [`crate::StufferShack::read`] was called on a record stored with
+    /// [`crate::headers::RecordFlags::Lz4`]; a compressed value can't be
+    /// handed back as a borrow into the map, so [`crate::StufferShack::read_into`]
+    /// must be used instead.
+    #[error("record at offset {offset} is compressed, use read_into instead")]
+    CompressedRecordNeedsOwnedRead {
+        /// Byte offset of the compressed record.
+        offset: u64,
+    },
+    /// Growing the companion `.idx` file in place failed, e.g. a failed
+    /// `set_len` or remap. See [`crate::index::IndexTable::grow`].
+    #[error("could not grow index file")]
+    IndexGrowIo(#[source] io::Error),
+    /// A value was requested at an offset that isn't a multiple of its
+    /// type's required alignment, which would make constructing a
+    /// reference there undefined behaviour. Every offset this crate itself
+    /// ever writes keeps records aligned, so this can only happen against a
+    /// corrupt file.
+    #[error("value of alignment {align} requested at misaligned offset {offset}")]
+    Misaligned { offset: u64, align: usize },
+    /// An index slot's control byte matched a probe's `h2`, but the slot
+    /// itself held the raw `0` sentinel this crate reserves to mean "empty"
+    /// (see [`crate::index`]'s module doc). A slot a probe can match always
+    /// stores `offset + 1`, so this can only happen against a corrupt `.idx`
+    /// file.
+    #[error("index slot matched probe but held the empty sentinel")]
+    IndexSlotEmpty,
 }
@@ -12,6 +12,48 @@ const MAGIC_BYTES: [u8; 16] = [
 /// Magic number used to check endianness.
 const ENDIANNESS_CHECK_CONST: u32 = 0xA1B2C3D4;
 
+/// Fixed part of [`RecordHeader`], i.e. everything before `key`: `value_length`
+/// (4) + `uncompressed_length` (4) + `flags` (1) + `_padding` (3). Kept a
+/// multiple of 4 so the compiler never has to insert padding of its own
+/// between the prefix and `key`. It still pads the struct's end up to a
+/// multiple of 4, so `mem::size_of::<RecordHeader<N>>()` only equals the
+/// exact formula `check_valid` asserts against when `key`'s length is a
+/// multiple of 4; for any other key length that assertion fails.
+const RECORD_HEADER_PREFIX: usize = 12;
+
+/// Whether newly written records are compressed, recorded once per database
+/// in `DatabaseHeader::compression`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CompressionType {
+    /// Values are written and read back as-is.
+    None,
+    /// Values are LZ4-compressed before being written.
+    Lz4,
+}
+
+impl CompressionType {
+    const NONE_TAG: u8 = 0;
+    const LZ4_TAG: u8 = 1;
+
+    /// Decodes a `DatabaseHeader::compression` byte, failing on anything
+    /// other than a tag this version of the crate understands.
+    pub(crate) fn decode(tag: u8) -> Result<Self, InvalidDatabaseError> {
+        match tag {
+            Self::NONE_TAG => Ok(CompressionType::None),
+            Self::LZ4_TAG => Ok(CompressionType::Lz4),
+            _ => Err(InvalidDatabaseError::UnknownCompressionType { compression: tag }),
+        }
+    }
+
+    /// Encodes for storage in `DatabaseHeader::compression`.
+    pub(crate) fn encode(self) -> u8 {
+        match self {
+            CompressionType::None => Self::NONE_TAG,
+            CompressionType::Lz4 => Self::LZ4_TAG,
+        }
+    }
+}
+
 /// Database header.
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
@@ -26,8 +68,17 @@ pub(crate) struct DatabaseHeader {
     pub(crate) next_insert: u64,
     /// The size of a key.
     pub(crate) key_length: u16,
+    /// Encoded `CompressionType` new writes use, see `CompressionType::decode`.
+    pub(crate) compression: u8,
+    /// Set to `1` whenever a handle to this database is opened and only
+    /// cleared back to `0` by a clean `Drop`, so it reads `1` on the next
+    /// open iff the previous process never got to close cleanly (crash,
+    /// `kill -9`, power loss). See [`Self::is_dirty`]/[`crate::recover`]:
+    /// the full WAL walk only needs to run in that case, or when the
+    /// companion index is missing outright.
+    pub(crate) dirty: u8,
     // Extra header space, intentionally left blank for future versions.
-    pub(crate) _padding: [u8; 30],
+    pub(crate) _padding: [u8; 28],
 }
 
 unsafe impl Pod for DatabaseHeader {}
@@ -43,11 +94,7 @@ impl DatabaseHeader {
 
         // Sanity check to ensure all of our data structures have the right size.
         assert_eq!(mem::size_of::<DatabaseHeader>(), 64);
-        assert_eq!(
-            mem::size_of::<RecordHeader<N>>(),
-            // Four bytes (for the offset pointer) + the actual length of the array.
-            4 + key_length
-        );
+        assert_eq!(mem::size_of::<RecordHeader<N>>(), RECORD_HEADER_PREFIX + key_length);
 
         if self.magic_bytes != MAGIC_BYTES {
             return Err(InvalidDatabaseError::InvalidMagic);
@@ -77,8 +124,9 @@ impl DatabaseHeader {
         Ok(())
     }
 
-    /// Resets the database header.
-    pub(crate) fn reset<N>(&mut self)
+    /// Resets the database header, selecting `compression` for values
+    /// written from here on.
+    pub(crate) fn reset<N>(&mut self, compression: CompressionType)
     where
         N: ArrayLength<u8>,
         N::ArrayType: Copy,
@@ -89,8 +137,76 @@ impl DatabaseHeader {
         self.endianness_check = ENDIANNESS_CHECK_CONST;
         self.version = 1;
         self.next_insert = mem::size_of::<Self>() as u64;
-        self.key_length = mem::size_of::<GenericArray<u8, N>> as u16;
-        self._padding = [0; 30];
+        self.key_length = mem::size_of::<GenericArray<u8, N>>() as u16;
+        self.compression = compression.encode();
+        self.dirty = 1;
+        self._padding = [0; 28];
+    }
+
+    /// Whether the database was left open by a process that never cleared
+    /// `dirty` via a clean `Drop`, meaning a crash (or similar) may have left
+    /// the WAL's tail or the companion index inconsistent with `next_insert`.
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty != 0
+    }
+
+    /// Marks the database dirty: called on every open, so that if this
+    /// process doesn't get to close cleanly, the next open's [`Self::is_dirty`]
+    /// check correctly falls back to a full WAL walk.
+    pub(crate) fn mark_dirty(&mut self) {
+        self.dirty = 1;
+    }
+
+    /// Marks the database cleanly closed. Called from `StufferShack`'s
+    /// `Drop` impl.
+    pub(crate) fn mark_clean(&mut self) {
+        self.dirty = 0;
+    }
+}
+
+/// How a record's value is encoded on disk. Stored as a raw `u8` in
+/// [`RecordHeader::flags`] rather than as this enum directly, since
+/// `RecordHeader` is [`Pod`] and gets cast straight out of the mmap --
+/// materializing an enum from an arbitrary/corrupt byte would be undefined
+/// behaviour, so the byte is validated through [`Self::decode`] instead.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RecordFlags {
+    /// `value` is stored as-is; `value_length` is also its true length.
+    Raw,
+    /// `value` is LZ4-compressed; decompress into a buffer of
+    /// `uncompressed_length` bytes to recover it.
+    Lz4,
+    /// A write into this range was reserved but never completed (see
+    /// `StufferShack::write`'s rollback path). `value_length` still spans
+    /// the full range that was reserved, so the record can be skipped over
+    /// correctly by `recover`'s WAL walk, but it holds no real data and is
+    /// never indexed.
+    Aborted,
+}
+
+impl RecordFlags {
+    const RAW_TAG: u8 = 0;
+    const LZ4_TAG: u8 = 1;
+    const ABORTED_TAG: u8 = 2;
+
+    /// Decodes a `RecordHeader::flags` byte, failing on any tag this
+    /// version of the crate doesn't know about.
+    pub(crate) fn decode(tag: u8) -> Result<Self, InvalidDatabaseError> {
+        match tag {
+            Self::RAW_TAG => Ok(RecordFlags::Raw),
+            Self::LZ4_TAG => Ok(RecordFlags::Lz4),
+            Self::ABORTED_TAG => Ok(RecordFlags::Aborted),
+            _ => Err(InvalidDatabaseError::UnknownRecordFlags { flags: tag }),
+        }
+    }
+
+    /// Encodes for storage in `RecordHeader::flags`.
+    pub(crate) fn encode(self) -> u8 {
+        match self {
+            RecordFlags::Raw => Self::RAW_TAG,
+            RecordFlags::Lz4 => Self::LZ4_TAG,
+            RecordFlags::Aborted => Self::ABORTED_TAG,
+        }
     }
 }
 
@@ -102,8 +218,18 @@ where
     N: ArrayLength<u8>,
     N::ArrayType: Copy,
 {
-    /// The length of the data value.
+    /// The length of the value as stored on disk: the compressed length
+    /// when `flags` is [`RecordFlags::Lz4`], otherwise equal to
+    /// `uncompressed_length`.
     pub(crate) value_length: u32,
+    /// The value's length once decompressed. Equal to `value_length` for
+    /// [`RecordFlags::Raw`] records; used to size the buffer `read_into`
+    /// decompresses into.
+    pub(crate) uncompressed_length: u32,
+    /// See [`RecordFlags`].
+    pub(crate) flags: u8,
+    /// Reserved for future use; see [`RECORD_HEADER_PREFIX`].
+    pub(crate) _padding: [u8; 3],
     /// The key, typically a hash.
     pub(crate) key: GenericArray<u8, N>,
 }
@@ -0,0 +1,524 @@
+//! On-disk open-addressing index.
+//!
+//! Keeping the key -> offset index purely in RAM (as a `HashMap`) means
+//! every `open_disk` has to replay the entire WAL to rebuild it, which is
+//! O(records) in the size of the database. This module instead persists
+//! the index as a flat, mmap-backed hash table (in the style of `odht`),
+//! so opening an existing database is O(1): the table is read directly out
+//! of the map, no deserialization required.
+//!
+//! Layout (all within a single mmap, separate from the WAL's mmap):
+//!
+//! ```text
+//! IndexHeader || [control byte; capacity] || [slot; capacity]
+//! ```
+//!
+//! `capacity` is always a power of two and a multiple of [`GROUP_WIDTH`].
+//! Each control byte is either [`EMPTY`] or the top 7 bits of the entry's
+//! hash ([`h2`]), letting most probes be rejected by comparing a whole
+//! group of [`GROUP_WIDTH`] control bytes at once instead of touching the
+//! (larger) slot array. Collisions are resolved by linear probing over
+//! groups. Each slot is a single `u64` holding the record's data offset
+//! plus one, so that `0` unambiguously marks an empty slot (a data offset
+//! of `0` is otherwise perfectly valid).
+
+use std::{
+    fs,
+    hash::Hasher,
+    io::{self, Write},
+    marker::PhantomData,
+    mem,
+    path::{Path, PathBuf},
+};
+
+use fxhash::FxHasher;
+use generic_array::{ArrayLength, GenericArray};
+use memmap::{MmapMut, MmapOptions};
+
+use crate::{
+    backend::StoreBackend,
+    error::InvalidDatabaseError,
+    offset::SafeOffset,
+    unchecked_cast::{Pod, UncheckedCast, UncheckedCastMut},
+    DbLen,
+};
+
+/// Offset `0` always fits within a freshly sized index map (it's at least
+/// `region_size(MIN_CAPACITY)` bytes, and `CONTROL_OFFSET` alone is
+/// `size_of::<IndexHeader>()`), so reading/writing the header there can
+/// never hit [`InvalidDatabaseError::OffsetOverflow`].
+const HEADER_OFFSET_IN_BOUNDS: &str = "index header offset is always in-bounds";
+
+/// Number of control bytes examined per probe.
+const GROUP_WIDTH: usize = 16;
+
+/// Control byte sentinel marking an empty slot.
+const EMPTY: u8 = 0xFF;
+
+/// Smallest table ever allocated.
+const MIN_CAPACITY: usize = GROUP_WIDTH;
+
+/// Magic bytes identifying an index file.
+const INDEX_MAGIC_BYTES: [u8; 16] = [
+    b'S', b'T', b'U', b'F', b'F', b'E', b'R', b'_', b'I', b'N', b'D', b'E', b'X', b'_', b'_', b'_',
+];
+
+/// Header stored at the start of the index's backing map.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct IndexHeader {
+    /// See [`INDEX_MAGIC_BYTES`].
+    magic_bytes: [u8; 16],
+    /// Number of slots in the table. Always a power of two.
+    capacity: u64,
+    /// Number of occupied slots.
+    len: u64,
+    /// Reserved for future use.
+    _padding: [u8; 32],
+}
+
+unsafe impl Pod for IndexHeader {}
+
+impl IndexHeader {
+    fn reset(&mut self, capacity: u64) {
+        self.magic_bytes = INDEX_MAGIC_BYTES;
+        self.capacity = capacity;
+        self.len = 0;
+        self._padding = [0; 32];
+    }
+}
+
+/// Offset of the control byte array within the index map.
+const CONTROL_OFFSET: usize = mem::size_of::<IndexHeader>();
+
+/// An mmap-backed, open-addressing hash table mapping a fixed-size key to a
+/// [`DbLen`] offset.
+#[derive(Debug)]
+pub(crate) struct IndexTable<N> {
+    map: MmapMut,
+    /// The companion `.idx` file backing `map`, kept open so [`Self::grow`]
+    /// can `set_len` it in place. `None` for ephemeral/in-memory tables,
+    /// which grow by just allocating a bigger anonymous map instead.
+    file: Option<fs::File>,
+    _key: PhantomData<N>,
+}
+
+impl<N> IndexTable<N>
+where
+    N: ArrayLength<u8>,
+    N::ArrayType: Copy,
+{
+    /// Opens (or creates) the index file alongside `db`.
+    ///
+    /// Returns the table along with whether it was just created, in which
+    /// case it holds no entries and the caller is responsible for
+    /// repopulating it (e.g. by replaying the WAL) if the database itself
+    /// wasn't also freshly created.
+    pub(crate) fn open_disk<P: AsRef<Path>>(db: P) -> io::Result<(Self, bool)> {
+        let path = index_path(db.as_ref());
+
+        let backing_file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        let file_len = backing_file.metadata()?.len();
+        let needs_init = file_len == 0;
+
+        let map_len = if needs_init {
+            region_size(MIN_CAPACITY)
+        } else {
+            file_len as usize
+        };
+        backing_file.set_len(map_len as u64)?;
+        backing_file.flush()?;
+
+        let map = unsafe { MmapOptions::new().len(map_len).map_mut(&backing_file)? };
+
+        Ok((
+            Self::new_in_map(map, needs_init, Some(backing_file)),
+            needs_init,
+        ))
+    }
+
+    /// Creates an in-memory index table, for ephemeral databases and tests.
+    pub(crate) fn open_ephemeral() -> io::Result<Self> {
+        let map = unsafe {
+            MmapOptions::new()
+                .len(region_size(MIN_CAPACITY))
+                .map_anon()?
+        };
+        Ok(Self::new_in_map(map, true, None))
+    }
+
+    fn new_in_map(mut map: MmapMut, needs_init: bool, file: Option<fs::File>) -> Self {
+        if needs_init {
+            map.at_mut::<IndexHeader>(0)
+                .expect(HEADER_OFFSET_IN_BOUNDS)
+                .reset(MIN_CAPACITY as u64);
+            init_control_bytes(&mut map, MIN_CAPACITY);
+        }
+
+        IndexTable {
+            map,
+            file,
+            _key: PhantomData,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.map
+            .at::<IndexHeader>(0)
+            .expect(HEADER_OFFSET_IN_BOUNDS)
+            .capacity as usize
+    }
+
+    fn len(&self) -> usize {
+        self.map
+            .at::<IndexHeader>(0)
+            .expect(HEADER_OFFSET_IN_BOUNDS)
+            .len as usize
+    }
+
+    /// Byte offset of `slot` within a table of the given `capacity`. Both
+    /// `capacity` (read from the on-disk [`IndexHeader`]) and `slot` (derived
+    /// from a hash) can be arbitrary in a corrupt file, so every step is
+    /// checked.
+    fn slot_offset(capacity: usize, slot: usize) -> Result<usize, InvalidDatabaseError> {
+        SafeOffset::new(slot as u64)
+            .checked_mul(mem::size_of::<u64>() as u64)?
+            .checked_add(CONTROL_OFFSET as u64)?
+            .checked_add(capacity as u64)?
+            .to_usize()
+    }
+
+    /// Clears every entry, keeping the table's current capacity.
+    ///
+    /// Used when `recover` needs to rebuild the index from the WAL even
+    /// though the companion file already existed (e.g. it's stale after an
+    /// unclean shutdown): `raw_insert`'s rebuild path assumes it's starting
+    /// from an empty table, so reusing a table that still holds old entries
+    /// risks duplicate or stale slots surviving the rebuild.
+    pub(crate) fn clear(&mut self) {
+        let capacity = self.capacity();
+        self.map
+            .at_mut::<IndexHeader>(0)
+            .expect(HEADER_OFFSET_IN_BOUNDS)
+            .reset(capacity as u64);
+        init_control_bytes(&mut self.map, capacity);
+    }
+
+    fn load_factor_exceeded(&self) -> bool {
+        // Load factor of ~87%, expressed without floating point: len * 8 > capacity * 7.
+        self.len() * 8 > self.capacity() * 7
+    }
+
+    /// Looks up `key`, reading the WAL `data` map to verify full key equality
+    /// on a control-byte match, and returns the stored data offset if found.
+    pub(crate) fn lookup<B: StoreBackend>(
+        &self,
+        data: &B,
+        key: &GenericArray<u8, N>,
+    ) -> Result<Option<DbLen>, InvalidDatabaseError> {
+        let hash = hash_key(key);
+        let capacity = self.capacity();
+
+        for group_start in probe_sequence(hash, capacity) {
+            let group = Group::load(&self.map[CONTROL_OFFSET + group_start..][..GROUP_WIDTH]);
+
+            for bit in group.match_byte(h2(hash)) {
+                let slot = group_start + bit;
+                let raw = *self.map.at::<u64>(Self::slot_offset(capacity, slot)?)?;
+                let candidate_offset = decode_slot(raw)?;
+                let (header, _) = crate::record_at_offset::<N, _>(data, candidate_offset)?;
+                if header.key == *key {
+                    return Ok(Some(candidate_offset));
+                }
+            }
+
+            if group.match_empty().any() {
+                return Ok(None);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Inserts `key -> offset`, growing (and rehashing) the table first if
+    /// its load factor already exceeds ~87% (so the insert itself can take
+    /// it up to, at most, one free slot short of full).
+    ///
+    /// Growing needs to recover the original keys of every existing entry,
+    /// which is why it is handed the WAL's `data` map: the key isn't stored
+    /// in the index itself, only its offset.
+    pub(crate) fn insert<B: StoreBackend>(
+        &mut self,
+        data: &B,
+        key: &GenericArray<u8, N>,
+        offset: DbLen,
+    ) -> Result<(), InvalidDatabaseError> {
+        if self.load_factor_exceeded() {
+            self.grow(data)?;
+        }
+
+        let hash = hash_key(key);
+        self.raw_insert(hash, offset)?;
+
+        let header = self.map.at_mut::<IndexHeader>(0).expect(HEADER_OFFSET_IN_BOUNDS);
+        header.len += 1;
+        Ok(())
+    }
+
+    /// Inserts a hash/offset pair into the table assuming it is not already
+    /// present and has room (used both by [`Self::insert`] and by
+    /// [`Self::grow`], which hashes each key it reads back out of the WAL
+    /// itself and passes the hash in).
+    fn raw_insert(&mut self, hash: u64, offset: DbLen) -> Result<(), InvalidDatabaseError> {
+        let capacity = self.capacity();
+
+        for group_start in probe_sequence(hash, capacity) {
+            let group = Group::load(&self.map[CONTROL_OFFSET + group_start..][..GROUP_WIDTH]);
+
+            if let Some(bit) = group.match_empty().lowest_set_bit() {
+                let slot = group_start + bit;
+                self.map[CONTROL_OFFSET + slot] = h2(hash);
+                *self.map.at_mut::<u64>(Self::slot_offset(capacity, slot)?)? =
+                    encode_slot(offset)?;
+                return Ok(());
+            }
+        }
+
+        unreachable!("index table probed without finding a free slot; grow() should have run")
+    }
+
+    /// Doubles the table's capacity and rehashes every entry into it.
+    ///
+    /// For a file-backed table, this grows the companion `.idx` file itself
+    /// (`set_len` + remap) rather than diverting to an anonymous map, so the
+    /// larger table is actually persisted -- growing into anonymous memory
+    /// here would silently lose every entry inserted after the first grow
+    /// on the next `open_disk`, since the on-disk file would stay at its
+    /// original (smaller) size.
+    fn grow<B: StoreBackend>(&mut self, data: &B) -> Result<(), InvalidDatabaseError> {
+        let old_capacity = self.capacity();
+        let new_capacity = old_capacity * 2;
+        let new_size = region_size(new_capacity);
+
+        // For a file-backed table `new_map` maps the very same file as
+        // `self.map`, so resetting it below would also wipe the entries about
+        // to be rehashed; read them from a private copy of the old table.
+        let mut old_map = unsafe { MmapOptions::new().len(self.map.len()).map_anon() }
+            .map_err(InvalidDatabaseError::IndexGrowIo)?;
+        old_map.copy_from_slice(&self.map);
+
+        let mut new_map = match &self.file {
+            Some(file) => {
+                file.set_len(new_size as u64)
+                    .map_err(InvalidDatabaseError::IndexGrowIo)?;
+                unsafe { MmapOptions::new().len(new_size).map_mut(file) }
+                    .map_err(InvalidDatabaseError::IndexGrowIo)?
+            }
+            None => unsafe {
+                MmapOptions::new()
+                    .len(new_size)
+                    .map_anon()
+                    .expect("failed to grow in-memory index table")
+            },
+        };
+        new_map
+            .at_mut::<IndexHeader>(0)
+            .expect(HEADER_OFFSET_IN_BOUNDS)
+            .reset(new_capacity as u64);
+        init_control_bytes(&mut new_map, new_capacity);
+
+        self.map = new_map;
+        for slot in 0..old_capacity {
+            let control = old_map[CONTROL_OFFSET + slot];
+            if control == EMPTY {
+                continue;
+            }
+            let raw = *old_map.at::<u64>(Self::slot_offset(old_capacity, slot)?)?;
+            let offset = decode_slot(raw)?;
+            let (header, _) = crate::record_at_offset::<N, _>(data, offset)?;
+            let hash = hash_key(&header.key);
+            self.raw_insert(hash, offset)?;
+        }
+
+        self.map
+            .at_mut::<IndexHeader>(0)
+            .expect(HEADER_OFFSET_IN_BOUNDS)
+            .len = old_map
+            .at::<IndexHeader>(0)
+            .expect(HEADER_OFFSET_IN_BOUNDS)
+            .len;
+        Ok(())
+    }
+}
+
+/// Encodes a data offset for storage in a slot: `offset + 1`, so that `0`
+/// unambiguously marks an empty slot (see the module doc). Checked rather
+/// than a bare `+ 1` so an offset of `u64::MAX` fails cleanly instead of
+/// wrapping to the empty sentinel itself.
+fn encode_slot(offset: DbLen) -> Result<u64, InvalidDatabaseError> {
+    SafeOffset::new(offset).checked_add(1).map(SafeOffset::get)
+}
+
+/// Decodes a slot's raw `u64` back into the data offset it represents.
+///
+/// `raw` comes straight out of the mmap, so a corrupt `.idx` file could hold
+/// `0` (the empty sentinel) in a slot whose control byte still happens to
+/// match a probe's `h2` -- checked here rather than assumed, to keep this
+/// module's hardening consistent with `unchecked_cast`'s alignment checks.
+fn decode_slot(raw: u64) -> Result<DbLen, InvalidDatabaseError> {
+    raw.checked_sub(1).ok_or(InvalidDatabaseError::IndexSlotEmpty)
+}
+
+fn init_control_bytes(map: &mut MmapMut, capacity: usize) {
+    map[CONTROL_OFFSET..(CONTROL_OFFSET + capacity)].fill(EMPTY);
+}
+
+/// Total byte size of the index region (header, control bytes and slots)
+/// for a table with the given capacity.
+fn region_size(capacity: usize) -> usize {
+    CONTROL_OFFSET + capacity + capacity * mem::size_of::<u64>()
+}
+
+/// Path of the companion index file for a given database file.
+fn index_path(db: &Path) -> PathBuf {
+    let mut path = db.as_os_str().to_owned();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+/// Hashes a key with a fast, non-cryptographic hash. The index only ever
+/// stores a derived 7-bit control byte plus the record's offset, so full
+/// key equality is always double-checked against the WAL on a match.
+fn hash_key<N>(key: &GenericArray<u8, N>) -> u64
+where
+    N: ArrayLength<u8>,
+    N::ArrayType: Copy,
+{
+    let mut hasher = FxHasher::default();
+    hasher.write(key.as_slice());
+    hasher.finish()
+}
+
+/// The bucket a hash starts probing at, aligned to a group boundary.
+#[inline]
+fn h1(hash: u64, capacity: usize) -> usize {
+    (hash as usize & (capacity - 1)) & !(GROUP_WIDTH - 1)
+}
+
+/// The 7-bit control byte derived from a hash. Never equal to [`EMPTY`].
+#[inline]
+fn h2(hash: u64) -> u8 {
+    (hash >> 57) as u8
+}
+
+/// Yields successive group-aligned bucket indices to probe for `hash`,
+/// wrapping around the table. `capacity` must be a multiple of
+/// [`GROUP_WIDTH`], which guarantees every yielded index plus `GROUP_WIDTH`
+/// stays within bounds.
+fn probe_sequence(hash: u64, capacity: usize) -> impl Iterator<Item = usize> {
+    let mut pos = h1(hash, capacity);
+    let mut remaining = capacity / GROUP_WIDTH;
+    std::iter::from_fn(move || {
+        if remaining == 0 {
+            return None;
+        }
+        remaining -= 1;
+        let current = pos;
+        pos = (pos + GROUP_WIDTH) & (capacity - 1);
+        Some(current)
+    })
+}
+
+/// A bitmask over a [`Group`]'s lanes, one bit per matching control byte.
+#[derive(Clone, Copy)]
+struct BitMask(u16);
+
+impl BitMask {
+    #[inline]
+    fn any(self) -> bool {
+        self.0 != 0
+    }
+
+    #[inline]
+    fn lowest_set_bit(self) -> Option<usize> {
+        if self.0 == 0 {
+            None
+        } else {
+            Some(self.0.trailing_zeros() as usize)
+        }
+    }
+}
+
+impl Iterator for BitMask {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        let bit = self.lowest_set_bit()?;
+        self.0 &= self.0 - 1;
+        Some(bit)
+    }
+}
+
+/// A group of [`GROUP_WIDTH`] control bytes that can be compared in one go.
+#[derive(Clone, Copy)]
+struct Group([u8; GROUP_WIDTH]);
+
+impl Group {
+    #[inline]
+    fn load(bytes: &[u8]) -> Self {
+        let mut group = [0u8; GROUP_WIDTH];
+        group.copy_from_slice(bytes);
+        Group(group)
+    }
+
+    /// Returns a mask with a bit set for every lane equal to `byte`.
+    #[inline]
+    fn match_byte(self, byte: u8) -> BitMask {
+        #[cfg(target_arch = "x86_64")]
+        {
+            match_byte_sse2(self.0, byte)
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            match_byte_scalar(self.0, byte)
+        }
+    }
+
+    /// Returns a mask with a bit set for every empty lane.
+    #[inline]
+    fn match_empty(self) -> BitMask {
+        self.match_byte(EMPTY)
+    }
+}
+
+/// Portable, scalar fallback for targets without a fast SIMD byte-compare.
+#[inline]
+#[allow(dead_code)]
+fn match_byte_scalar(group: [u8; GROUP_WIDTH], byte: u8) -> BitMask {
+    let mut mask = 0u16;
+    for (i, &b) in group.iter().enumerate() {
+        if b == byte {
+            mask |= 1 << i;
+        }
+    }
+    BitMask(mask)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn match_byte_sse2(group: [u8; GROUP_WIDTH], byte: u8) -> BitMask {
+    use std::arch::x86_64::{_mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8};
+
+    // SSE2 is part of the x86-64 baseline ABI, so this is always available.
+    unsafe {
+        let group = _mm_loadu_si128(group.as_ptr() as *const _);
+        let needle = _mm_set1_epi8(byte as i8);
+        let eq = _mm_cmpeq_epi8(group, needle);
+        BitMask(_mm_movemask_epi8(eq) as u16)
+    }
+}
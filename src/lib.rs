@@ -5,341 +5,847 @@
 //! Record := Length || Hash || Value
 //! WAL := [Record]
 //!
-//! Overhead per stored value on disk is 4 bytes per record.
+//! Overhead per stored value on disk is 12 bytes per record (a
+//! `value_length` and `uncompressed_length` pair, a flags byte, and its own
+//! padding) plus the fixed key length, plus whatever extra padding
+//! `padded_record_span` adds to keep the following record's header aligned.
+
+mod backend;
+mod error;
+mod headers;
+mod index;
+mod offset;
+mod unchecked_cast;
 
 use std::{
-    collections::HashMap,
+    borrow::Cow,
     fs,
-    hash::Hash,
-    io::{self, Seek, SeekFrom, Write},
     marker::PhantomData,
     mem,
+    ops::Deref,
     path::Path,
-    sync::atomic::AtomicU64,
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock, RwLockReadGuard,
+    },
 };
 
 use generic_array::{ArrayLength, GenericArray};
-use memmap::{MmapMut, MmapOptions};
-use thiserror::Error;
 
-// TODO: Use im-rs for parallel read/write.
-// TODO: Use serialization of in-memory index, storing offset, to allow fast recovery of WAL.
-// TODO: Persist write offset.
+use backend::{AnonBackend, FileBackend, MemBackend, StoreBackend};
+use error::InvalidDatabaseError;
+pub use error::StufferShackError;
+use headers::{CompressionType, DatabaseHeader, RecordFlags, RecordHeader};
+use index::IndexTable;
+use offset::SafeOffset;
+use unchecked_cast::{UncheckedCast, UncheckedCastMut};
+
 // TODO: Consider packing.
 
-// const MAP_SIZE: usize = usize::MAX / 2;
-const MAP_SIZE: usize = u32::MAX as usize; // TODO: Figure out why allocation fails.
+/// Smallest size a freshly created backend reserves. `ensure_capacity`
+/// doubles from here as `write` needs more room, instead of reserving the
+/// old hard `u32::MAX` ceiling (and its sparse-file allocation failures) up
+/// front.
+const INITIAL_BACKEND_SIZE: usize = 1024 * 1024;
 
 type ItemLen = u32;
 type DbLen = u64;
-const ITEM_LEN_SIZE: usize = mem::size_of::<ItemLen>();
-const DB_LEN_SIZE: usize = mem::size_of::<DbLen>();
-const MAGIC_BYTES: [u8; 16] = [
-    b'S', b'T', b'U', b'F', b'F', b'E', b'R', b'_', b'S', b'H', b'A', b'C', b'K', b'_', b'_', b'_',
-];
-const ENDIANNESS_CHECK_CONST: u32 = 0xA1B2C3D4;
+
+/// Offset `0` always fits within a backend at least large enough to hold a
+/// `DatabaseHeader`, which every `open_*`/`new_in_map` constructor guarantees
+/// before this crate ever reads or writes through it.
+const HEADER_OFFSET_IN_BOUNDS: &str = "database header offset is always in-bounds";
 
 #[derive(Debug)]
-struct StufferShack<N: ArrayLength<u8>> {
-    /// Maps a key to an offset.
-    index: HashMap<GenericArray<u8, N>, DbLen>,
-    /// Internal data map.
-    data: MmapMut,
+struct StufferShack<N: ArrayLength<u8>, B> {
+    /// Maps a key to an offset, persisted in its own mmap-backed region.
+    ///
+    /// Insertion is serialized behind an `RwLock` rather than being made
+    /// fully lock-free: any number of `read()` calls can still run
+    /// concurrently with each other, they only ever block against the
+    /// (comparatively rare) `insert` a concurrent `write()` performs.
+    index: RwLock<IndexTable<N>>,
+    /// Internal data store.
+    ///
+    /// Guarded by an `RwLock` (rather than being a bare `B`) purely so
+    /// `ensure_capacity` can grow it: every ordinary read/write of record
+    /// bytes only ever takes the cheap, concurrently-shared read side, and
+    /// only a `grow` -- which remaps the region and can move its base
+    /// address -- needs the exclusive write side. That in turn is what
+    /// makes growing sound: while any reader holds the read lock (see
+    /// [`BorrowedValue`]), `grow` can't run, so a pointer derived from the
+    /// map is never invalidated out from under it.
+    data: RwLock<B>,
+    /// High-water mark of *reserved* (not necessarily yet committed) bytes.
+    /// Only ever moves forward via a compare-and-swap in `write`, so
+    /// concurrent writers always claim disjoint `[start, start + record_size)` ranges to write into.
+    /// The persisted commit pointer lives in `DatabaseHeader::next_insert`
+    /// instead (see [`StufferShack::committed`]), not here, since it must
+    /// survive a restart.
+    reserved: AtomicU64,
+    /// Compression new writes use. Decided once at open time -- either from
+    /// the caller (for a freshly created database) or from the persisted
+    /// `DatabaseHeader::compression` (for an existing one) -- and cached
+    /// here since it can't change for as long as this handle stays open.
+    compression: CompressionType,
+    /// The error that made `recover` truncate the WAL on open, if it had to
+    /// (e.g. a crash cut off the last write). `None` if recovery either
+    /// didn't need to run (see [`DatabaseHeader::is_dirty`]) or ran cleanly
+    /// to the end. Surfaced via [`Self::recovery_truncation`] instead of
+    /// logged directly from library code, so embedders decide how (or
+    /// whether) to report it.
+    recovered_truncation: Option<InvalidDatabaseError>,
     _key: PhantomData<N>,
 }
 
-impl<N> StufferShack<N>
+impl<N> StufferShack<N, FileBackend>
 where
     N: ArrayLength<u8>,
     N::ArrayType: Copy,
 {
-    fn open_disk<P: AsRef<Path>>(db: P) -> io::Result<Self> {
-        let mut backing_file = fs::OpenOptions::new()
+    fn open_disk<P: AsRef<Path>>(
+        db: P,
+        compression: CompressionType,
+    ) -> Result<Self, StufferShackError> {
+        let backing_file = fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(db)?;
+            .open(db.as_ref())
+            .map_err(StufferShackError::DatabaseOpen)?;
+
+        let existing_len = backing_file
+            .metadata()
+            .map_err(StufferShackError::DatabaseOpen)?
+            .len();
+        let needs_init = existing_len == 0;
+
+        // Reopening an existing database keeps whatever size it already
+        // grew to; a fresh one starts modest and grows on demand via
+        // `StufferShack::ensure_capacity`.
+        let initial_len = existing_len.max(INITIAL_BACKEND_SIZE as u64) as usize;
+        let data = FileBackend::open(backing_file, initial_len).map_err(StufferShackError::DatabaseOpen)?;
+
+        let (index, index_needs_rebuild) =
+            IndexTable::open_disk(db.as_ref()).map_err(StufferShackError::DatabaseOpen)?;
+        Self::new(data, index, needs_init, index_needs_rebuild, compression)
+    }
+}
 
-        let file_len = backing_file.seek(SeekFrom::End(0))?;
-        backing_file.seek(SeekFrom::Start(0))?;
+impl<N> StufferShack<N, AnonBackend>
+where
+    N: ArrayLength<u8>,
+    N::ArrayType: Copy,
+{
+    fn open_ephemeral(size: usize, compression: CompressionType) -> Result<Self, StufferShackError> {
+        let data = AnonBackend::new(size).map_err(StufferShackError::DatabaseOpen)?;
+        let index = IndexTable::open_ephemeral().map_err(StufferShackError::DatabaseOpen)?;
+        Self::new(data, index, true, false, compression)
+    }
+}
 
-        // TODO: Is this necessary outside OS X?
-        backing_file.set_len(MAP_SIZE as u64)?;
-        backing_file.flush()?;
+impl<N> StufferShack<N, MemBackend>
+where
+    N: ArrayLength<u8>,
+    N::ArrayType: Copy,
+{
+    /// Opens a database backed by a plain heap buffer, with no OS mmap
+    /// involved at all. Used by tests (and anywhere mmap is unavailable)
+    /// instead of paying for a real anonymous map every run.
+    fn open_in_memory(
+        size: usize,
+        compression: CompressionType,
+    ) -> Result<Self, StufferShackError> {
+        let data = MemBackend::new(size);
+        let index = IndexTable::open_ephemeral().map_err(StufferShackError::DatabaseOpen)?;
+        Self::new(data, index, true, false, compression)
+    }
+}
 
-        let data = unsafe { MmapOptions::new().len(MAP_SIZE).map_mut(&backing_file)? };
+impl<N, B> StufferShack<N, B>
+where
+    N: ArrayLength<u8>,
+    N::ArrayType: Copy,
+    B: StoreBackend,
+{
+    fn new(
+        mut data: B,
+        mut index: IndexTable<N>,
+        needs_init: bool,
+        index_needs_rebuild: bool,
+        compression: CompressionType,
+    ) -> Result<Self, StufferShackError> {
+        let mut recovered_truncation = None;
+
+        let compression = if needs_init {
+            data.at_mut::<DatabaseHeader>(0)
+                .expect(HEADER_OFFSET_IN_BOUNDS)
+                .reset::<N>(compression);
+            compression
+        } else {
+            let header = data
+                .at::<DatabaseHeader>(0)
+                .expect(HEADER_OFFSET_IN_BOUNDS);
+            header.check_valid::<N>().map_err(StufferShackError::DatabaseInit)?;
+
+            // Walking the whole WAL is only needed to recover from a state
+            // the persisted metadata can't otherwise be trusted for: either
+            // the companion index didn't survive (so it has to be rebuilt
+            // from scratch anyway), or the last process holding this
+            // database open never got to clear `dirty`, meaning a crash may
+            // have left `next_insert`/the index referring to a record whose
+            // write never finished. Otherwise the previous clean close
+            // already left both in a consistent state and `next_insert`
+            // alone is enough.
+            let was_dirty = header.is_dirty();
+            if index_needs_rebuild || was_dirty {
+                if was_dirty && !index_needs_rebuild {
+                    // The index file survived, but it may be stale (missing
+                    // the tail end of a crash-interrupted write, or holding
+                    // entries past a `next_insert` recover is about to rewind
+                    // back). `recover_record`'s rebuild path assumes it's
+                    // starting from an empty table, so clear it first --
+                    // otherwise rebuilding would re-insert entries on top of
+                    // ones that are now stale or duplicated.
+                    index.clear();
+                }
+                recovered_truncation = recover::<N, _>(&mut data, &mut index, true);
+            }
 
-        // TODO: Probably not necessary? Forgetting the backing file, so it won't be closed here.
-        mem::forget(backing_file);
+            let stored = data
+                .at::<DatabaseHeader>(0)
+                .expect(HEADER_OFFSET_IN_BOUNDS)
+                .compression;
+            CompressionType::decode(stored).map_err(StufferShackError::DatabaseInit)?
+        };
+
+        data.at_mut::<DatabaseHeader>(0)
+            .expect(HEADER_OFFSET_IN_BOUNDS)
+            .mark_dirty();
+
+        let reserved = AtomicU64::new(
+            data.at::<DatabaseHeader>(0)
+                .expect(HEADER_OFFSET_IN_BOUNDS)
+                .next_insert,
+        );
 
-        Self::new(data, file_len == 0)
+        Ok(StufferShack {
+            index: RwLock::new(index),
+            data: RwLock::new(data),
+            reserved,
+            compression,
+            recovered_truncation,
+            _key: PhantomData,
+        })
     }
 
-    fn open_ephemeral(size: usize) -> io::Result<Self> {
-        let data = unsafe { MmapOptions::new().len(size).map_anon()? };
-        Self::new(data, true)
+    /// The error that made recovery truncate the WAL on open, if it had to;
+    /// `None` if recovery didn't need to run or completed without issue. See
+    /// the `recovered_truncation` field doc for why this isn't just logged
+    /// directly from here.
+    #[allow(dead_code)]
+    fn recovery_truncation(&self) -> Option<&InvalidDatabaseError> {
+        self.recovered_truncation.as_ref()
     }
 
-    fn new(mut data: MmapMut, needs_init: bool) -> io::Result<Self> {
-        // let header = &mut data[0..DB_HEADER_SIZE];
-
-        // let mut index = HashMap::new();
-        // if dbg!(needs_init) {
-        //     // Database not initialized, write the magic bytes and initial length.
-        //     header[0..MAGIC_BYTES_LEN].copy_from_slice(&MAGIC_BYTES);
-        //     let initial_len: DbLen = 0;
-        //     header[MAGIC_BYTES_LEN..].copy_from_slice(&initial_len.to_le_bytes());
-        // } else if &header[0..MAGIC_BYTES_LEN] != &MAGIC_BYTES[..] {
-        //     return Err(io::Error::new(
-        //         io::ErrorKind::Other,
-        //         "database has invalid magic header",
-        //     ));
-        // }
-
-        // // We're already initialized, so walk entire data to restore the index.
-        // let total_size = store_length(&data) as usize;
-        // let mut cur = DB_HEADER_SIZE;
-        // while cur < total_size {
-        //     let record = load_record::<K>(&data, cur as u64);
-        //     // length, hash, data. We only need the hash.
-        //     // TODO: Unsafe-cast record header instead.
-        //     let hash_bytes = &record[ITEM_LEN_SIZE..(ITEM_LEN_SIZE + mem::size_of::<K>())];
-
-        //     // TODO: Find something better (moot with record header).
-        //     let hash_ptr: *const K = hash_bytes.as_ptr() as *const K;
-        //     let hash = unsafe { *hash_ptr };
-
-        //     index.insert(hash, cur as DbLen);
-        //     cur += record.len();
-        // }
-        // dbg!(index.len());
-
-        // Ok(StufferShack {
-        //     index,
-        //     data,
-        //     _key: PhantomData,
-        // })
-        todo!()
+    /// Loads the persisted commit pointer (`DatabaseHeader::next_insert`).
+    ///
+    /// The header lives directly in `data`'s mapped bytes, so a plain
+    /// (non-atomic) read of `next_insert` would race with a concurrent
+    /// `write()` advancing it. Reinterpreting the same 8 bytes as an
+    /// `AtomicU64` is sound, since `AtomicU64` has identical size, alignment
+    /// and bit-validity to `u64`; taking `data`'s read lock around it is what
+    /// additionally guards against a concurrent `ensure_capacity` remapping
+    /// the region this pointer was just read from.
+    fn committed(&self) -> DbLen {
+        let data = self.data.read().unwrap();
+        commit_pointer(&*data).load(Ordering::SeqCst)
     }
 
     fn size(&self) -> u64 {
-        store_length(&self.data)
+        self.committed()
     }
 
-    /// Store the length of the db without the header in the db header.
-    fn write_store_length(&mut self, size: DbLen) {
-        todo!()
-        // let dest = &mut self.data[MAGIC_BYTES_LEN..(MAGIC_BYTES_LEN + DB_LEN_SIZE)];
-        // dest.copy_from_slice(&size.to_le_bytes());
-    }
-
-    /// Reserves a record in the db with the specified size.
+    /// Grows `data` so it's at least `required` bytes long, doubling
+    /// capacity each step so the number of remaps stays logarithmic in the
+    /// database's eventual size (instead of the old fixed `u32::MAX`
+    /// up-front reservation).
     ///
-    /// Returns the data offset and a writable slice.
-    fn reserve_record(&mut self, record_size: ItemLen) -> (DbLen, &mut [u8]) {
-        let old_store_length = store_length(&self.data);
-        let new_store_length = old_store_length + record_size as DbLen;
-        self.write_store_length(new_store_length);
-        let data = &mut self.data[data_offset_to_memory_offset(old_store_length)
-            ..data_offset_to_memory_offset(new_store_length)];
-        (old_store_length, data)
+    /// Checks under a read lock first -- once a database has grown past its
+    /// first few records, capacity is almost always already sufficient --
+    /// and only takes the write lock, which blocks every concurrent
+    /// `read`/`write` until it completes, when a remap is actually needed.
+    fn ensure_capacity(&self, required: DbLen) -> Result<(), StufferShackError> {
+        if self.data.read().unwrap().len() as DbLen >= required {
+            return Ok(());
+        }
+
+        let mut data = self.data.write().unwrap();
+        let mut new_len = (data.len() as DbLen).max(INITIAL_BACKEND_SIZE as DbLen);
+        while new_len < required {
+            new_len *= 2;
+        }
+
+        if new_len > data.len() as DbLen {
+            data.grow(new_len as usize)
+                .map_err(StufferShackError::BackendGrow)?;
+        }
+
+        Ok(())
     }
 
-    // TODO: Allow parallel writes.
-    fn write(&mut self, key: GenericArray<u8, N>, value: &[u8]) {
+    /// Writes `key -> value`, returning once the write is committed.
+    ///
+    /// # Durability guarantee
+    ///
+    /// A value becomes visible to `read()` (and is covered by the persisted
+    /// `next_insert`) iff its bytes have been fully written *and* `committed`
+    /// has advanced past its offset. Nothing here flushes the backend to
+    /// stable storage: for a file-backed database, committed bytes reach the
+    /// file whenever the OS writes back the mapped pages. Each writer first claims an exclusive `[start, start +
+    /// record_size)` byte range -- this never blocks, since racing writers
+    /// simply land on disjoint ranges -- and writes its record into that
+    /// range. Only then does it publish, by CAS-ing `committed` from `start`
+    /// to `start + record_size`: a writer whose range doesn't start exactly
+    /// where `committed` currently is spins until every earlier-reserved
+    /// writer has published first. This keeps `committed` advancing in the
+    /// same order records were reserved in, so a reader that observes
+    /// `committed` having passed an offset never observes a gap where that
+    /// record's bytes aren't there yet. The index is only ever inserted into
+    /// *after* `committed` has advanced past the record: a crash in between
+    /// would otherwise leave a persisted index entry pointing past
+    /// `next_insert`, resolving to a record the commit pointer doesn't cover.
+    fn write(&self, key: GenericArray<u8, N>, value: &[u8]) -> Result<(), StufferShackError> {
         assert!(
-            self.index.get(&key).is_none(),
+            self.index
+                .read()
+                .unwrap()
+                .lookup(&*self.data.read().unwrap(), &key)
+                .map_err(StufferShackError::InvalidAccess)?
+                .is_none(),
             "rewriting keys is not supported"
         );
 
-        // Get insertion point.
-        let insertion_point = todo!();
-        let next_insertion_point =
-            write_record::<N>(&mut self.data, insertion_point, key.as_ref(), value);
+        let (flags, stored): (RecordFlags, Cow<[u8]>) = match self.compression {
+            CompressionType::None => (RecordFlags::Raw, Cow::Borrowed(value)),
+            CompressionType::Lz4 => (
+                RecordFlags::Lz4,
+                Cow::Owned(lz4_flex::block::compress(value)),
+            ),
+        };
+
+        let header_size = mem::size_of::<RecordHeader<N>>() as DbLen;
+        // Padded out to `RecordHeader<N>`'s alignment (see
+        // `padded_record_span`) so the *next* record is guaranteed to start
+        // at an aligned offset too -- required for `UncheckedCast::at` to
+        // ever produce a valid `&RecordHeader<N>` out of it.
+        let record_size = padded_record_span::<N>(header_size + stored.len() as DbLen)
+            .map_err(StufferShackError::InvalidAccess)?;
+
+        // Confirm there's room for the record *before* claiming its range,
+        // not after: once `reserved` has moved past `[start, start +
+        // record_size)`, `committed` can only ever catch up to that exact
+        // point (see `try_commit`), so discovering a capacity failure
+        // afterwards would strand the range and wedge every later writer
+        // behind it forever. Looping here instead just means a concurrent
+        // writer racing for the same speculative range retries.
+        let start = loop {
+            let candidate_start = self.reserved.load(Ordering::SeqCst);
+            self.ensure_capacity(candidate_start + record_size)?;
+            if self
+                .reserved
+                .compare_exchange_weak(
+                    candidate_start,
+                    candidate_start + record_size,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                )
+                .is_ok()
+            {
+                break candidate_start;
+            }
+        };
+
+        let data = self.data.read().unwrap();
+
+        // SAFETY: `[start, start + record_size)` was just reserved
+        // exclusively for this write by the loop above, so no other writer
+        // can be touching the same bytes concurrently, and holding `data`'s
+        // read lock for the duration rules out a concurrent `ensure_capacity`
+        // remap moving the region out from under it.
+        let written = unsafe {
+            write_record::<N, _>(
+                &*data,
+                start,
+                key.as_ref(),
+                &stored,
+                flags,
+                value.len() as u32,
+            )
+        };
+
+        if let Err(err) = written {
+            // The range is reserved but never got a valid record written
+            // into it -- this can only happen against an already-corrupt
+            // database (the offset arithmetic in `write_record` overflowing).
+            // Mark it aborted rather than returning early and leaving
+            // `committed` (and every writer behind this one) wedged forever.
+            unsafe {
+                abort_record::<N, _>(&*data, start, stored.len() as u32).expect(
+                    "data_offset_to_memory_offset succeeded moments ago for the same `start` \
+                     inside write_record, so it can't suddenly overflow here",
+                );
+            }
+            while !try_commit(&*data, start, record_size) {
+                std::hint::spin_loop();
+            }
+            return Err(StufferShackError::InvalidAccess(err));
+        }
 
-        // Update index.
-        self.index.insert(key, insertion_point);
+        while !try_commit(&*data, start, record_size) {
+            std::hint::spin_loop();
+        }
 
-        // Note: By updating the insertion point here, we gain some sort of transactional durability. Alternatively we can increase the insertion point sooner to gain parallel writes. (TODO) Adding a second insertion pointer would give us both.
-        todo!("update insertion point");
-    }
+        drop(data);
 
-    fn read(&self, key: &GenericArray<u8, N>) -> Option<&[u8]> {
-        let data_offset = *self.index.get(key)?;
-        todo!()
-        // let record = load_record::<K>(&self.data, data_offset);
+        self.index
+            .write()
+            .unwrap()
+            .insert(&*self.data.read().unwrap(), &key, start)
+            .map_err(StufferShackError::InvalidAccess)?;
 
-        // let value_slice = &record[(ITEM_LEN_SIZE + mem::size_of::<K>())..];
-        // Some(value_slice)
+        Ok(())
     }
-}
 
-/// Retrieves the length of the db without header from the db header.
-fn store_length(data: &MmapMut) -> DbLen {
-    todo!()
-    // DbLen::from_le_bytes(
-    //     data[MAGIC_BYTES_LEN..(MAGIC_BYTES_LEN + DB_LEN_SIZE)]
-    //         .try_into()
-    //         .unwrap(),
-    // )
-}
+    /// Reads `key`'s value as a zero-copy borrow into the map.
+    ///
+    /// Fails with [`InvalidDatabaseError::CompressedRecordNeedsOwnedRead`] if
+    /// the stored record is LZ4-compressed, since a decompressed value can't
+    /// be handed back as a borrow; use [`Self::read_into`] instead when
+    /// compression may be active.
+    ///
+    /// The returned [`BorrowedValue`] holds `data`'s read lock for as long as
+    /// it stays alive, which is what keeps the pointer it wraps valid: a
+    /// concurrent `ensure_capacity` can't remap the region until every such
+    /// guard is dropped.
+    fn read(&self, key: &GenericArray<u8, N>) -> Result<Option<BorrowedValue<'_, B>>, StufferShackError> {
+        let data = self.data.read().unwrap();
+        let data_offset = match self
+            .index
+            .read()
+            .unwrap()
+            .lookup(&*data, key)
+            .map_err(StufferShackError::InvalidAccess)?
+        {
+            Some(data_offset) => data_offset,
+            None => return Ok(None),
+        };
+        let (header, value) = record_at_offset::<N, _>(&*data, data_offset)
+            .map_err(StufferShackError::InvalidAccess)?;
+
+        match RecordFlags::decode(header.flags).map_err(StufferShackError::InvalidAccess)? {
+            RecordFlags::Raw => {
+                let ptr = value as *const [u8];
+                Ok(Some(BorrowedValue { _guard: data, ptr }))
+            }
+            RecordFlags::Lz4 => Err(StufferShackError::InvalidAccess(
+                InvalidDatabaseError::CompressedRecordNeedsOwnedRead { offset: data_offset },
+            )),
+        }
+    }
 
-/// Converts a database offset into a memory offset, which includes the header.
-fn data_offset_to_memory_offset(offset: DbLen) -> usize {
-    offset as usize + mem::size_of::<DatabaseHeader>()
-}
+    /// Reads `key`'s value into `buf` (cleared first), returning whether an
+    /// entry was found. Unlike [`Self::read`], this works regardless of
+    /// whether the record is stored raw or LZ4-compressed: a compressed
+    /// value is decompressed into `buf`, a raw one is just copied in, giving
+    /// one API that behaves the same whether or not compression is active.
+    fn read_into(
+        &self,
+        key: &GenericArray<u8, N>,
+        buf: &mut Vec<u8>,
+    ) -> Result<bool, StufferShackError> {
+        let data = self.data.read().unwrap();
+        let data_offset = match self
+            .index
+            .read()
+            .unwrap()
+            .lookup(&*data, key)
+            .map_err(StufferShackError::InvalidAccess)?
+        {
+            Some(data_offset) => data_offset,
+            None => return Ok(false),
+        };
+        let (header, stored) = record_at_offset::<N, _>(&*data, data_offset)
+            .map_err(StufferShackError::InvalidAccess)?;
+
+        buf.clear();
+        match RecordFlags::decode(header.flags).map_err(StufferShackError::InvalidAccess)? {
+            RecordFlags::Raw => buf.extend_from_slice(stored),
+            RecordFlags::Lz4 => {
+                buf.resize(header.uncompressed_length as usize, 0);
+                lz4_flex::block::decompress_into(stored, buf).map_err(|_| {
+                    StufferShackError::InvalidAccess(InvalidDatabaseError::DecompressionFailed {
+                        offset: data_offset,
+                    })
+                })?;
+            }
+        }
 
-/// Database header.
-#[derive(Clone, Copy, Debug)]
-#[repr(C)]
-struct DatabaseHeader {
-    // Magic bytes, see `MAGIC_BYTES`.
-    magic_bytes: [u8; 16],
-    // The value `ENDIANNESS_CHECK_CONST` (will be encoded using native endianness).
-    endianness_check: u32,
-    // Database version. Currently must be 1.
-    version: u32,
-    // The insertion pointer for new values.
-    insertion_pointer: u32,
-    /// The size of a key.
-    key_length: u16,
-    // Extra header space, intentionally left blank for future versions.
-    _padding: [u8; 34],
+        Ok(true)
+    }
 }
 
-#[derive(Copy, Clone, Debug, Error)]
-enum InvalidDatabaseError {
-    /// First bytes were not equal to the magic file header.
-    #[error("invalid magic at start of file")]
-    InvalidMagic,
-    /// The endianness constant found in the header differed from the stored one.
-    #[error("database failed endianness check")]
-    EndiannessMismatch,
-    /// Version mismatch.
-    #[error("version not supported: {version}")]
-    UnsupportedVersion {
-        /// The version found in the database file.
-        version: u32,
-    },
-    /// The compile-time configured key length does not match opened db.
-    #[error("key length mismatch (expected {expected}, actual {actual}")]
-    KeyLengthMismatch {
-        /// Version that was expected, based on how the database was instantiated.
-        expected: u16,
-        /// Version found in the database.
-        actual: u16,
-    },
-    /// The key length given at compile time is too large to fit a `u16`.
-    #[error("key length overflow")]
-    KeyLengthOverflow,
+impl<N, B> Drop for StufferShack<N, B>
+where
+    N: ArrayLength<u8>,
+    N::ArrayType: Copy,
+    B: StoreBackend,
+{
+    /// Clears `DatabaseHeader::dirty`, so the next `open_disk` of this
+    /// database can tell it was closed cleanly and skip straight past
+    /// `recover`'s full WAL walk.
+    fn drop(&mut self) {
+        if let Ok(mut data) = self.data.write() {
+            if let Ok(header) = data.at_mut::<DatabaseHeader>(0) {
+                header.mark_clean();
+            }
+        }
+    }
 }
 
-impl DatabaseHeader {
-    /// Checks that the header is valid for keys with the specified size.
-    fn is_valid<N>(&self) -> Result<(), InvalidDatabaseError>
-    where
-        N: ArrayLength<u8>,
-        N::ArrayType: Copy,
-    {
-        let key_length = mem::size_of::<GenericArray<u8, N>>();
-
-        // Sanity check to ensure all of our data structures have the right size.
-        assert_eq!(mem::size_of::<DatabaseHeader>(), 64);
-        assert_eq!(
-            mem::size_of::<RecordHeader<N>>(),
-            // Four bytes (for the offset pointer) + the actual length of the array.
-            4 + key_length
-        );
-
-        if self.magic_bytes != MAGIC_BYTES {
-            return Err(InvalidDatabaseError::InvalidMagic);
-        }
+/// A value handed back by [`StufferShack::read`], borrowed directly out of
+/// the map.
+///
+/// Bundling the read guard with the pointer (rather than handing back a bare
+/// `&[u8]`) is what lets [`StufferShack::ensure_capacity`] grow the backend
+/// at all: the guard keeps `data`'s read lock held for as long as this value
+/// is alive, and `ensure_capacity` needs that same lock's write side to
+/// remap, so no outstanding `BorrowedValue` can ever be invalidated by a
+/// concurrent grow.
+struct BorrowedValue<'a, B> {
+    _guard: RwLockReadGuard<'a, B>,
+    ptr: *const [u8],
+}
 
-        if self.endianness_check != ENDIANNESS_CHECK_CONST {
-            return Err(InvalidDatabaseError::EndiannessMismatch);
-        }
+impl<'a, B> Deref for BorrowedValue<'a, B> {
+    type Target = [u8];
 
-        if self.version != 1 {
-            return Err(InvalidDatabaseError::UnsupportedVersion {
-                version: self.version,
-            });
-        }
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` was derived from `_guard`'s backing region and
+        // stays valid for as long as `_guard` -- and thus its read lock --
+        // is held, since only a write-locked `grow` can move or invalidate
+        // it.
+        unsafe { &*self.ptr }
+    }
+}
 
-        if key_length > u16::MAX as usize {
-            return Err(InvalidDatabaseError::KeyLengthOverflow);
-        }
+/// Reinterprets `data`'s `DatabaseHeader::next_insert` field as an
+/// `AtomicU64`.
+///
+/// Deliberately not `data.at::<DatabaseHeader>(0)` -- that returns a
+/// `&DatabaseHeader` borrowed from `&[u8]`, and reinterpreting a shared
+/// reference's target as an `AtomicU64` is undefined behaviour (`[u8]`
+/// carries no interior mutability of its own). Going through `write_ptr`
+/// and `addr_of_mut!` instead never materializes that intermediate
+/// reference, so the field's provenance stays the raw allocation's, which
+/// does support this.
+fn commit_pointer<B: StoreBackend>(data: &B) -> &AtomicU64 {
+    let header_ptr = data.write_ptr() as *mut DatabaseHeader;
+    let next_insert_ptr = unsafe { ptr::addr_of_mut!((*header_ptr).next_insert) } as *const AtomicU64;
+    unsafe { &*next_insert_ptr }
+}
 
-        if self.key_length != key_length as u16 {
-            return Err(InvalidDatabaseError::KeyLengthMismatch {
-                expected: key_length as u16,
-                actual: self.key_length,
-            });
-        }
+/// Attempts to publish `[start, start + record_size)` by CAS-ing the commit
+/// pointer from `start` to `start + record_size`.
+///
+/// Takes the `data` guard the caller already holds (see
+/// [`StufferShack::write`]) rather than locking internally: `write` spins on
+/// this in a loop while its own `self.data.read()` guard is still held, and
+/// `RwLock` doesn't support recursively re-acquiring a read lock while one is
+/// already held on the same thread -- a writer queued behind it (e.g. a
+/// concurrent `ensure_capacity` waiting for the write lock) would make a
+/// nested `read()` here block forever.
+fn try_commit<B: StoreBackend>(data: &B, start: DbLen, record_size: DbLen) -> bool {
+    commit_pointer(data)
+        .compare_exchange_weak(start, start + record_size, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+}
 
-        Ok(())
-    }
+/// Converts a database offset into a memory offset. Database offsets already
+/// count the `DatabaseHeader` in front of the first record, so this only
+/// narrows to `usize`, failing instead of truncating.
+fn data_offset_to_memory_offset(offset: DbLen) -> Result<usize, InvalidDatabaseError> {
+    SafeOffset::new(offset).to_usize()
 }
 
-/// Record header.
-#[repr(C)]
-#[derive(Copy, Clone, Debug)]
-struct RecordHeader<N>
+/// Rounds `unpadded` (a record's `header_size + value.len()`) up to the next
+/// multiple of `RecordHeader<N>`'s alignment.
+///
+/// Records are laid out back-to-back on disk with nothing in between, so
+/// without this, the next record would start wherever the previous one's
+/// value happened to end -- not necessarily aligned. Every record reserves
+/// this padded span (see [`StufferShack::write`]), which keeps every
+/// record's start aligned as long as the very first one is (it is: the WAL
+/// begins right after `DatabaseHeader`, whose size is asserted to be a
+/// multiple of 4 in [`headers::DatabaseHeader::check_valid`]).
+fn padded_record_span<N>(unpadded: DbLen) -> Result<DbLen, InvalidDatabaseError>
 where
     N: ArrayLength<u8>,
     N::ArrayType: Copy,
 {
-    /// The length of the data value.
-    value_length: u32,
-    /// The key, typically a hash.
-    key: GenericArray<u8, N>,
+    let align = mem::align_of::<RecordHeader<N>>() as DbLen;
+    let remainder = unpadded % align;
+    if remainder == 0 {
+        Ok(unpadded)
+    } else {
+        SafeOffset::new(unpadded)
+            .checked_add(align - remainder)
+            .map(SafeOffset::get)
+    }
 }
 
 /// Retrieve a record (with header) at offset.
 ///
 /// Given a specific data offset, returns the record header and data slice.
 #[inline]
-fn record_at_offset<N>(data: &MmapMut, data_offset: DbLen) -> (&RecordHeader<N>, &[u8])
+fn record_at_offset<N, B>(
+    data: &B,
+    data_offset: DbLen,
+) -> Result<(&RecordHeader<N>, &[u8]), InvalidDatabaseError>
 where
     N: ArrayLength<u8>,
     N::ArrayType: Copy,
+    B: StoreBackend,
 {
     let header_size = mem::size_of::<RecordHeader<N>>();
 
-    let start = data_offset_to_memory_offset(data_offset);
-    let header_ptr = start as *const RecordHeader<N>;
-
-    // TODO: FIX POTENTIAL ALIGNMENT ISSUES.
-    let header = unsafe { header_ptr.as_ref() }.expect("DID YOU FIX THE ALIGNMENT ISSUES?");
-
-    let value_slice = &data[start..(start + header_size)];
-    (header, value_slice)
+    let start = data_offset_to_memory_offset(data_offset)?;
+    let header = data.at::<RecordHeader<N>>(start)?;
+
+    let value_start = SafeOffset::new(start as u64)
+        .checked_add(header_size as u64)?
+        .to_usize()?;
+    let value_end = SafeOffset::new(value_start as u64)
+        .checked_add(header.value_length as u64)?
+        .to_usize()?;
+    let value = &data[value_start..value_end];
+    Ok((header, value))
 }
 
-/// Write a record at specified location.
+/// Writes a record at the specified location through a shared reference.
 ///
 /// Returns the next available `data_offset` after the write.
-fn write_record<N>(data: &mut MmapMut, data_offset: DbLen, key: &[u8], value: &[u8]) -> DbLen
+///
+/// # Safety
+///
+/// The caller must have exclusively reserved `[data_offset, data_offset +
+/// header_size + value.len())`, e.g. via a CAS on a shared high-water-mark
+/// counter, so that no other writer can be writing into the same range
+/// concurrently. Taking `data` by shared reference (rather than `&mut B`) is
+/// what lets multiple writers call this at once for disjoint ranges; that in
+/// turn is only sound because the actual writes go through
+/// `StoreBackend::write_ptr`, never through a `&[u8]`/`&mut [u8]` reborrow of
+/// `data` -- the latter would make writing through the resulting pointer
+/// undefined behaviour under Stacked/Tree Borrows, since `[u8]` has no
+/// interior mutability of its own.
+unsafe fn write_record<N, B>(
+    data: &B,
+    data_offset: DbLen,
+    key: &[u8],
+    value: &[u8],
+    flags: RecordFlags,
+    uncompressed_length: u32,
+) -> Result<DbLen, InvalidDatabaseError>
 where
     N: ArrayLength<u8>,
     N::ArrayType: Copy,
+    B: StoreBackend,
 {
     let header_size = mem::size_of::<RecordHeader<N>>();
-    let start = data_offset_to_memory_offset(data_offset);
-    let header_ptr = start as *mut RecordHeader<N>;
+    let start = data_offset_to_memory_offset(data_offset)?;
 
-    // TODO: FIX POTENTIAL ALIGNMENT ISSUES.
-    let header = unsafe { header_ptr.as_mut() }.expect("DID YOU FIX THE ALIGNMENT ISSUES?");
     assert!(
         value.len() < u32::MAX as usize,
         "value too large to be stored"
     );
-    header.value_length = value.len() as u32;
-    header.key.copy_from_slice(key);
 
-    let value_slice = &mut data[start..(start + header_size)];
-    value_slice.copy_from_slice(value);
+    let value_start = SafeOffset::new(start as u64)
+        .checked_add(header_size as u64)?
+        .to_usize()?;
+
+    let base = data.write_ptr();
+    let header_ptr = base.add(start) as *mut RecordHeader<N>;
+    (*header_ptr).value_length = value.len() as u32;
+    (*header_ptr).uncompressed_length = uncompressed_length;
+    (*header_ptr).flags = flags.encode();
+    (*header_ptr)._padding = [0; 3];
+    (*header_ptr).key.copy_from_slice(key);
+
+    let value_ptr = base.add(value_start);
+    ptr::copy_nonoverlapping(value.as_ptr(), value_ptr, value.len());
+
+    let record_end = SafeOffset::new(data_offset)
+        .checked_add(header_size as u64)?
+        .checked_add(value.len() as u64)?;
+    padded_record_span::<N>(record_end.get())
+}
 
-    data_offset + header_size as DbLen + value.len() as DbLen
+/// Overwrites the record at `data_offset` with a zero-value,
+/// [`RecordFlags::Aborted`] placeholder spanning `value_length` bytes.
+///
+/// Used when a write fails after its `[data_offset, data_offset +
+/// record_size)` range has already been reserved (see
+/// [`StufferShack::write`]): `committed` can only ever advance past a
+/// reserved range in the order it was reserved, so leaving a failed write's
+/// bytes untouched would strand every later writer behind it forever. An
+/// aborted record keeps the span skippable -- both by `recover`'s WAL walk
+/// and by anyone else who might otherwise try to interpret it as a real
+/// header -- while still letting `committed` advance over it.
+///
+/// # Safety
+///
+/// Same contract as [`write_record`]: the caller must have exclusively
+/// reserved this range.
+unsafe fn abort_record<N, B>(
+    data: &B,
+    data_offset: DbLen,
+    value_length: u32,
+) -> Result<(), InvalidDatabaseError>
+where
+    N: ArrayLength<u8>,
+    N::ArrayType: Copy,
+    B: StoreBackend,
+{
+    let start = data_offset_to_memory_offset(data_offset)?;
+    let header_ptr = data.write_ptr().add(start) as *mut RecordHeader<N>;
+    (*header_ptr).value_length = value_length;
+    (*header_ptr).uncompressed_length = 0;
+    (*header_ptr).flags = RecordFlags::Aborted.encode();
+    (*header_ptr)._padding = [0; 3];
+    Ok(())
+}
+
+/// Walks the WAL from the first record up to the header's `next_insert`,
+/// validating every record along the way.
+///
+/// If a crash cut off the very last write, the tail record will be
+/// incomplete; rather than failing to open the database, recovery stops at
+/// the last fully-written record and rewinds `next_insert` to its end,
+/// returning the error that triggered the truncation (already carrying the
+/// offset it happened at, via `TruncatedRecord`/`RecordSizeOverflow`) rather
+/// than logging it itself -- logging from library code would deny embedders
+/// any say over how (or whether) it's reported. `None` means every record up
+/// to `next_insert` validated cleanly. When `rebuild_index` is set (the
+/// companion index file didn't already exist, or was cleared because it
+/// might be stale), every visited record is also reinserted into `index`.
+fn recover<N, B>(
+    data: &mut B,
+    index: &mut IndexTable<N>,
+    rebuild_index: bool,
+) -> Option<InvalidDatabaseError>
+where
+    N: ArrayLength<u8>,
+    N::ArrayType: Copy,
+    B: StoreBackend,
+{
+    let valid_end = data
+        .at::<DatabaseHeader>(0)
+        .expect(HEADER_OFFSET_IN_BOUNDS)
+        .next_insert;
+    let mut offset = mem::size_of::<DatabaseHeader>() as DbLen;
+    let mut truncated_at = None;
+
+    while offset < valid_end {
+        match recover_record::<N, _>(data, index, offset, valid_end, rebuild_index) {
+            Ok(record_end) => offset = record_end,
+            Err(err) => {
+                truncated_at = Some(err);
+                break;
+            }
+        }
+    }
+
+    data.at_mut::<DatabaseHeader>(0)
+        .expect(HEADER_OFFSET_IN_BOUNDS)
+        .next_insert = offset;
+
+    truncated_at
+}
+
+/// Validates the record at `offset` and, if `rebuild_index` is set,
+/// reinserts it into `index`. Split out of [`recover`] so that both the
+/// "record too large/truncated" and "offset arithmetic overflowed" failure
+/// cases are handled identically by the caller's truncate-and-stop logic.
+fn recover_record<N, B>(
+    data: &mut B,
+    index: &mut IndexTable<N>,
+    offset: DbLen,
+    valid_end: DbLen,
+    rebuild_index: bool,
+) -> Result<DbLen, InvalidDatabaseError>
+where
+    N: ArrayLength<u8>,
+    N::ArrayType: Copy,
+    B: StoreBackend,
+{
+    let record_end = validate_record::<N, _>(data, offset, valid_end)?;
+
+    if rebuild_index {
+        let (header, _) = record_at_offset::<N, _>(data, offset)?;
+        // An aborted record never made it into the index in the first
+        // place (see `StufferShack::write`), so rebuilding skips it too.
+        if RecordFlags::decode(header.flags)? != RecordFlags::Aborted {
+            let key = header.key;
+            index.insert(data, &key, offset)?;
+        }
+    }
+
+    Ok(record_end)
+}
+
+/// Checks that the record at `offset` fits entirely within `[offset,
+/// valid_end)`, returning the offset the next record would start at.
+fn validate_record<N, B>(
+    data: &B,
+    offset: DbLen,
+    valid_end: DbLen,
+) -> Result<DbLen, InvalidDatabaseError>
+where
+    N: ArrayLength<u8>,
+    N::ArrayType: Copy,
+    B: StoreBackend,
+{
+    let header_size = mem::size_of::<RecordHeader<N>>() as DbLen;
+
+    let header_end = offset
+        .checked_add(header_size)
+        .ok_or(InvalidDatabaseError::RecordSizeOverflow {
+            offset,
+            size: header_size,
+        })?;
+    if header_end > valid_end {
+        return Err(InvalidDatabaseError::TruncatedRecord { offset });
+    }
+
+    let header = data.at::<RecordHeader<N>>(data_offset_to_memory_offset(offset)?)?;
+    // The next record starts after this one's *padded* span (see
+    // `padded_record_span`), not merely `header_size + value_length` --
+    // otherwise recovery would try to read the next `RecordHeader<N>` out of
+    // the padding bytes rather than where it was actually written.
+    let record_size = padded_record_span::<N>(header_size + header.value_length as DbLen)?;
+    let record_end =
+        offset
+            .checked_add(record_size)
+            .ok_or(InvalidDatabaseError::RecordSizeOverflow {
+                offset,
+                size: record_size,
+            })?;
+    if record_end > valid_end {
+        return Err(InvalidDatabaseError::TruncatedRecord { offset });
+    }
+
+    Ok(record_end)
 }
 
 #[cfg(test)]
@@ -347,6 +853,7 @@ mod tests {
     use std::mem;
 
     use super::StufferShack;
+    use crate::headers::CompressionType;
     use proptest::proptest;
     use proptest_derive::Arbitrary;
     use rand::{Rng, SeedableRng};
@@ -374,14 +881,41 @@ mod tests {
     proptest! {
         #[test]
         fn write_read_32_times(tasks: [WriteReadTask; 32]) {
-            let mut shack: StufferShack<Key> = StufferShack::open_ephemeral(200*1024*1024).unwrap();
+            // `open_in_memory` avoids paying for a real 200 MiB anonymous map
+            // on every proptest iteration; a plain heap buffer large enough
+            // for 32 small records is enough here.
+            let shack: StufferShack<Key, _> =
+                StufferShack::open_in_memory(64 * 1024, CompressionType::None).unwrap();
+
+            for task in &tasks {
+                shack.write(task.key(), task.value()).unwrap();
+            }
+
+            for task in &tasks {
+                assert_eq!(shack.read(&task.key()).unwrap().as_deref(), Some(task.value()))
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn write_read_into_32_times_lz4(tasks: [WriteReadTask; 32]) {
+            // Same shape as `write_read_32_times`, but with compression
+            // enabled: `read` must reject every record (they're all stored
+            // as `RecordFlags::Lz4`), so the round trip has to go through
+            // `read_into` instead.
+            let shack: StufferShack<Key, _> =
+                StufferShack::open_in_memory(64 * 1024, CompressionType::Lz4).unwrap();
 
             for task in &tasks {
-                shack.write(task.key(), task.value());
+                shack.write(task.key(), task.value()).unwrap();
             }
 
+            let mut buf = Vec::new();
             for task in &tasks {
-                assert_eq!(shack.read(&task.key()), Some(task.value()))
+                assert!(shack.read(&task.key()).is_err());
+                assert!(shack.read_into(&task.key(), &mut buf).unwrap());
+                assert_eq!(buf, task.value());
             }
         }
     }
@@ -440,7 +974,8 @@ mod tests {
         // TODO: Do on-disk.
         // let mut shack: StufferShack<Key> =
         // StufferShack::open_ephemeral(1024 * 1024 * 1024).unwrap();
-        let mut shack: StufferShack<Key> = StufferShack::open_disk("test.shack").unwrap();
+        let shack: StufferShack<Key, _> =
+            StufferShack::open_disk("test.shack", CompressionType::None).unwrap();
 
         let mut total_payload = 0usize;
 
@@ -449,14 +984,14 @@ mod tests {
         for (key, value) in data.take(count) {
             total_payload += key.len() + value.len();
 
-            shack.write(key, value);
+            shack.write(key, value).unwrap();
         }
 
         // Read back and verify entries.
         let data = DataGen::new();
         for (key, value) in data.take(count) {
-            let read_value = shack.read(&key);
-            assert_eq!(read_value, Some(value));
+            let read_value = shack.read(&key).unwrap();
+            assert_eq!(read_value.as_deref(), Some(value));
         }
 
         let db_size = shack.size() as usize;
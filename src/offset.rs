@@ -0,0 +1,64 @@
+//! Checked arithmetic for turning on-disk offsets into in-memory slice
+//! bounds.
+//!
+//! Every offset the crate computes either comes from a fixed, compile-time
+//! constant (always in range) or from a value read back out of a
+//! memory-mapped region (`DatabaseHeader::next_insert`, `IndexHeader::capacity`,
+//! a stored slot offset, a record's declared `value_length`, ...). A corrupt
+//! or truncated file can make the latter arbitrary, so [`SafeOffset`] routes
+//! every addition or multiplication through `u64::checked_*` and makes the
+//! eventual `u64` -> `usize` narrowing (needed to actually index a slice)
+//! fallible too, instead of wrapping or truncating silently.
+
+use std::convert::TryFrom;
+
+use crate::error::InvalidDatabaseError;
+
+/// A `u64` offset that only grows through checked arithmetic.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SafeOffset(u64);
+
+impl SafeOffset {
+    /// Wraps an already-known-valid base offset.
+    pub(crate) fn new(base: u64) -> Self {
+        SafeOffset(base)
+    }
+
+    /// Adds `operand`, failing with [`InvalidDatabaseError::OffsetOverflow`]
+    /// instead of wrapping past `u64::MAX`.
+    pub(crate) fn checked_add(self, operand: u64) -> Result<Self, InvalidDatabaseError> {
+        self.0
+            .checked_add(operand)
+            .map(SafeOffset)
+            .ok_or(InvalidDatabaseError::OffsetOverflow {
+                base: self.0,
+                operand,
+            })
+    }
+
+    /// Multiplies by `operand`, failing the same way as [`Self::checked_add`].
+    pub(crate) fn checked_mul(self, operand: u64) -> Result<Self, InvalidDatabaseError> {
+        self.0
+            .checked_mul(operand)
+            .map(SafeOffset)
+            .ok_or(InvalidDatabaseError::OffsetOverflow {
+                base: self.0,
+                operand,
+            })
+    }
+
+    /// Narrows to `usize` for slice indexing, failing rather than truncating
+    /// on platforms where `usize` is smaller than `u64`.
+    pub(crate) fn to_usize(self) -> Result<usize, InvalidDatabaseError> {
+        usize::try_from(self.0).map_err(|_| InvalidDatabaseError::OffsetOverflow {
+            base: self.0,
+            operand: 0,
+        })
+    }
+
+    /// Unwraps the underlying `u64`, for callers that only needed the
+    /// overflow checking and not a `usize` slice bound.
+    pub(crate) fn get(self) -> u64 {
+        self.0
+    }
+}
@@ -1,7 +1,11 @@
-use std::mem;
+use std::{
+    mem,
+    ops::{Deref, DerefMut},
+};
 
 use generic_array::{ArrayLength, GenericArray};
-use memmap::MmapMut;
+
+use crate::{error::InvalidDatabaseError, offset::SafeOffset};
 
 /// Trait indicating that all bit patterns of a value are valid.
 pub unsafe trait Pod: Copy {}
@@ -29,7 +33,11 @@ where
 /// Direct immutable access trait for unbounded memory regions.
 pub trait UncheckedCast {
     /// Interpret a memory location at the given offset as type `T`.
-    fn at<T>(&self, offset: usize) -> &T
+    ///
+    /// Fails with [`InvalidDatabaseError::OffsetOverflow`] rather than
+    /// wrapping or panicking if `offset + size_of::<T>()` doesn't fit a
+    /// `u64`/`usize`, which can only happen against a corrupt database.
+    fn at<T>(&self, offset: usize) -> Result<&T, InvalidDatabaseError>
     where
         T: Pod;
 }
@@ -37,37 +45,73 @@ pub trait UncheckedCast {
 /// Direct mutable access trait for unbounded memory regions.
 pub trait UncheckedCastMut {
     /// Interpret a memory location at the given offset as type `T`.
-    fn at_mut<T>(&mut self, offset: usize) -> &mut T
+    ///
+    /// See [`UncheckedCast::at`] for the failure case.
+    fn at_mut<T>(&mut self, offset: usize) -> Result<&mut T, InvalidDatabaseError>
     where
         T: Pod;
 }
 
-impl UncheckedCast for MmapMut {
+// Blanket impls over anything that derefs to a byte slice, so every
+// `StoreBackend` (which all deref to `[u8]`, see `backend`) gets `at`/`at_mut`
+// for free, instead of casting being re-implemented per backend.
+impl<D> UncheckedCast for D
+where
+    D: Deref<Target = [u8]>,
+{
     #[inline(always)]
-    fn at<T>(&self, offset: usize) -> &T
+    fn at<T>(&self, offset: usize) -> Result<&T, InvalidDatabaseError>
     where
         T: Pod,
     {
-        let slice = &self[offset..(offset + mem::size_of::<T>())];
+        let end = SafeOffset::new(offset as u64)
+            .checked_add(mem::size_of::<T>() as u64)?
+            .to_usize()?;
+        check_aligned::<T>(offset)?;
+        let slice = &self[offset..end];
 
         let item_ptr = slice.as_ptr() as *const T;
-        let item = unsafe { &*item_ptr };
-
-        item
+        Ok(unsafe { &*item_ptr })
     }
 }
 
-impl UncheckedCastMut for MmapMut {
+impl<D> UncheckedCastMut for D
+where
+    D: DerefMut<Target = [u8]>,
+{
     #[inline(always)]
-    fn at_mut<T>(&mut self, offset: usize) -> &mut T
+    fn at_mut<T>(&mut self, offset: usize) -> Result<&mut T, InvalidDatabaseError>
     where
         T: Pod,
     {
-        let slice = &mut self[offset..(offset + mem::size_of::<T>())];
+        let end = SafeOffset::new(offset as u64)
+            .checked_add(mem::size_of::<T>() as u64)?
+            .to_usize()?;
+        check_aligned::<T>(offset)?;
+        let slice = &mut self[offset..end];
 
         let item_ptr = slice.as_mut_ptr() as *mut T;
-        let item = unsafe { &mut *item_ptr };
+        Ok(unsafe { &mut *item_ptr })
+    }
+}
 
-        item
+/// Checks that `offset` is a multiple of `T`'s required alignment.
+///
+/// Records are laid out back-to-back on disk with no compiler-inserted
+/// padding, so without this, a type like `RecordHeader<N>` (alignment 4,
+/// from its two `u32` fields) could be cast out of an odd offset -- e.g.
+/// whenever the previous record's value has an odd length. Constructing a
+/// `&T`/`&mut T` at a misaligned address is undefined behaviour, so this is
+/// checked up front rather than only being avoided by the writer's own
+/// padding (see `crate::padded_record_span`) happening to get it right.
+fn check_aligned<T>(offset: usize) -> Result<(), InvalidDatabaseError> {
+    let align = mem::align_of::<T>();
+    if offset % align == 0 {
+        Ok(())
+    } else {
+        Err(InvalidDatabaseError::Misaligned {
+            offset: offset as u64,
+            align,
+        })
     }
 }